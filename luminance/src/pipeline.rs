@@ -2,24 +2,194 @@
 //!
 //! This module gives you materials to build *dynamic* rendering **pipelines**. A `Pipeline`
 //! represents a functional stream that consumes geometric data and rasterizes them.
+//!
+//! This core crate only defines the pipeline-level trait/type surface – `HasPipeline`'s methods
+//! describe behavior (the pass-iteration loop, the batched draw loop, mapping `DepthTest`/
+//! `StencilTest` to hardware state) that whatever backend crate implements `HasPipeline` is
+//! responsible for; no such backend crate exists in this tree, so that behavior is documented but
+//! not implemented here.
 
 use blending;
 use framebuffer::{ColorSlot, DepthSlot, Framebuffer, HasFramebuffer};
+use pixel::Pixel;
 use shader::program::{HasProgram, Program};
 use tessellation::{HasTessellation, Tessellation};
-use texture::{Dimensionable, HasTexture, Layerable};
+use texture::{Dimensionable, HasTexture, Layerable, Texture};
 
 /// Trait to implement to add `Pipeline` support.
 pub trait HasPipeline: HasFramebuffer + HasProgram + HasTessellation + HasTexture + Sized {
   /// Execute a pipeline command, resulting in altering the embedded framebuffer.
-  fn run_pipeline<L, D, CS, DS>(cmd: &Pipeline<Self, L, D, CS, DS>)
+  ///
+  /// Implementors should run `cmd.passes`, in order, before `cmd`’s own shading commands, binding
+  /// and clearing each pass’s framebuffer in turn. Each of the framebuffer’s color attachments is
+  /// cleared with the matching entry of `cmd.clear_color`.
+  ///
+  /// `next_unit` is the first texture unit this pipeline (and everything it runs) is free to
+  /// allocate: units below it are reserved by whatever wraps this pipeline in a `Pipe`. Pass it
+  /// straight through to each of `cmd.passes` (they run one at a time, so they can all start from
+  /// it) and to `run_shading_command` for each of `cmd.shading_commands`.
+  // TODO: this only pins down the signature of the pass-iteration loop; the loop itself needs a
+  // backend crate to land before this request can be called fully done.
+  fn run_pipeline<L, D, CS, DS, SS>(cmd: &Pipeline<Self, L, D, CS, DS, SS>, next_unit: u32)
     where L: Layerable,
           D: Dimensionable,
           D::Size: Copy,
           CS: ColorSlot<Self, L, D>,
-          DS: DepthSlot<Self, L, D>;
+          DS: DepthSlot<Self, L, D>,
+          SS: StencilSlot<Self, L, D>;
   /// Execute a shading command.
-  fn run_shading_command<T>(shading_cmd: &ShadingCommand<Self, T>);
+  ///
+  /// `units` holds the texture unit each texture of the `Pipe` wrapping `shading_cmd` (if any)
+  /// got bound to, in the same order; it’s empty if `shading_cmd` wasn’t wrapped in a `Pipe`.
+  ///
+  /// `next_unit` is the first texture unit still free once `units` have been accounted for; pass
+  /// it to `Pipe::bind` for each of `shading_cmd.render_commands` (they run one at a time, so they
+  /// can all start from it), and use the units it returns for that render command’s `update`.
+  ///
+  /// For each render command, blending, depth test, stencil test and rasterization state should
+  /// be set up once and then each of the command’s `tessellations` drawn with that shared state,
+  /// rather than repeating the state change per tessellation. See `RenderCommand::depth_test` for
+  /// how its `comparison`/`write` are meant to map to hardware state.
+  fn run_shading_command<T>(shading_cmd: &ShadingCommand<Self, T>, units: &[u32], next_unit: u32);
+}
+
+/// Trait to implement to add stencil-test support to a `Framebuffer`. This mirrors `DepthSlot`:
+/// a type implementing `StencilSlot` knows how to turn itself into the stencil attachment (or
+/// the absence thereof) of a `Framebuffer`.
+///
+/// This core crate only defines the `StencilSlot`/`StencilTest` surface and threads it through
+/// `Pipeline`/`HasPipeline`; provisioning a stencil renderbuffer on `Framebuffer` and mapping a
+/// `StencilTest` to hardware state (`glStencilFunc`/`glStencilOp` and friends) is the backend's
+/// job (see the module-level note above).
+pub trait StencilSlot<C, L, D> where C: HasFramebuffer, L: Layerable, D: Dimensionable {
+  /// The stencil texture associated with this slot, if any.
+  type StencilTexture;
+
+  /// Retrieve the stencil texture of a `Framebuffer` using this slot, if it has one.
+  fn stencil_texture<CS, DS>(framebuffer: &Framebuffer<C, L, D, CS, DS, Self>) -> Option<&Self::StencilTexture>
+    where CS: ColorSlot<C, L, D>,
+          DS: DepthSlot<C, L, D>,
+          Self: Sized;
+}
+
+/// A comparison function. Used by the stencil and depth tests to decide whether a fragment
+/// passes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compare {
+  /// Never passes.
+  Never,
+  /// Passes if the incoming value is less than the stored value.
+  Less,
+  /// Passes if the incoming value is less than or equal to the stored value.
+  LEqual,
+  /// Passes if the incoming value is equal to the stored value.
+  Equal,
+  /// Passes if the incoming value is greater than the stored value.
+  Greater,
+  /// Passes if the incoming value is greater than or equal to the stored value.
+  GEqual,
+  /// Passes if the incoming value is not equal to the stored value.
+  NotEqual,
+  /// Always passes.
+  Always
+}
+
+/// An operation to apply to the stencil buffer, depending on the outcome of the stencil and
+/// depth tests.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StencilOp {
+  /// Keep the value currently in the stencil buffer.
+  Keep,
+  /// Set the value to 0.
+  Zero,
+  /// Replace the value with the stencil test’s reference value.
+  Replace,
+  /// Increment the value, clamping it at the maximum representable value.
+  Incr,
+  /// Increment the value, wrapping around to 0 on overflow.
+  IncrWrap,
+  /// Decrement the value, clamping it at 0.
+  Decr,
+  /// Decrement the value, wrapping around to the maximum representable value on underflow.
+  DecrWrap,
+  /// Bitwise-invert the value.
+  Invert
+}
+
+/// Depth test configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct DepthTest {
+  /// Comparison function used to decide whether a fragment passes the depth test.
+  pub comparison: Compare,
+  /// Should a fragment that passes the depth test have its depth written to the depth buffer?
+  pub write: bool
+}
+
+/// Stencil test configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct StencilTest {
+  /// Comparison function used to decide whether a fragment passes the stencil test.
+  pub func: Compare,
+  /// Reference value compared against the stencil buffer’s value.
+  pub reference: u8,
+  /// Mask applied to both the reference and the stored value before they’re compared.
+  pub read_mask: u8,
+  /// Mask applied to the value before it’s written to the stencil buffer.
+  pub write_mask: u8,
+  /// Operation to perform when the stencil test fails.
+  pub on_stencil_fail: StencilOp,
+  /// Operation to perform when the stencil test passes but the depth test fails.
+  pub on_depth_fail: StencilOp,
+  /// Operation to perform when both the stencil and depth tests pass.
+  pub on_pass: StencilOp
+}
+
+/// This trait is used to add existential quantification to `Texture`s so that a `Pipe` (and, in
+/// turn, a `Pipeline`, `ShadingCommand` or `RenderCommand`) can hold a heterogeneous list of
+/// textures – of possibly different layering, dimension or pixel format – to bind.
+pub trait SomeTexture<C> where C: HasTexture {
+  /// Bind this texture to the given texture unit.
+  fn bind(&self, unit: u32);
+}
+
+impl<C, L, D, P> SomeTexture<C> for Texture<C, L, D, P>
+    where C: HasTexture, L: Layerable, D: Dimensionable, P: Pixel {
+  fn bind(&self, unit: u32) {
+    C::bind_texture(self, unit);
+  }
+}
+
+/// A `Pipe` binds a list of textures to texture units before handing control to a downstream
+/// node `T` – typically a `Pipeline`, `ShadingCommand` or `RenderCommand`. For a `ShadingCommand`
+/// or `RenderCommand`, the unit each texture ends up bound to is handed to its `update` closure,
+/// in the same order as `textures`; a `Pipeline` has no `update` closure and only gets the first
+/// unit left free after `textures`.
+pub struct Pipe<'a, C, T> where C: 'a + HasTexture {
+  /// Textures to bind to texture units before running the downstream node.
+  pub textures: Vec<&'a SomeTexture<C>>,
+  /// The downstream node.
+  pub next: T
+}
+
+impl<'a, C, T> Pipe<'a, C, T> where C: 'a + HasTexture {
+  /// Wrap `next` so that `textures` get bound to texture units before it runs.
+  pub fn new(textures: Vec<&'a SomeTexture<C>>, next: T) -> Self {
+    Pipe {
+      textures: textures,
+      next: next
+    }
+  }
+
+  /// Bind `textures` to consecutive units starting at `next_unit`, returning the next unit still
+  /// free once they’re accounted for. Callers thread that value down to whatever runs after
+  /// `next`, instead of letting it re-enumerate units from zero and collide with `textures`.
+  pub fn bind(&self, next_unit: u32) -> u32 {
+    for (i, texture) in self.textures.iter().enumerate() {
+      texture.bind(next_unit + i as u32);
+    }
+
+    next_unit + self.textures.len() as u32
+  }
 }
 
 /// A dynamic rendering pipeline. A *pipeline* is responsible of rendering into a `Framebuffer`.
@@ -28,75 +198,154 @@ pub trait HasPipeline: HasFramebuffer + HasProgram + HasTessellation + HasTextur
 ///
 /// `D` refers to the `Dim` of the underlying `Framebuffer`.
 ///
-/// `CS` and `DS` are – respectively – the *color* and *depth* `Slot` of the underlying
-/// `Framebuffer`.
-pub struct Pipeline<'a, C, L, D, CS, DS>
+/// `CS`, `DS` and `SS` are – respectively – the *color*, *depth* and *stencil* `Slot` of the
+/// underlying `Framebuffer`.
+///
+/// `clear_color` holds one clear color per color attachment, which is what `CS` would need to
+/// support MRT (Multiple Render Targets) – a `ColorSlot` implemented for a tuple of pixel formats,
+/// producing a framebuffer with several color attachments written by the same fragment shader,
+/// useful for deferred shading’s G-buffers. That `ColorSlot`/`HasFramebuffer` generalization lives
+/// in the `framebuffer` module, which doesn’t exist in this tree; only this per-attachment
+/// `clear_color` is implemented here.
+// TODO: MRT support is scoped down to this per-attachment clear_color; the tuple ColorSlot/
+// HasFramebuffer generalization itself needs a follow-up once the framebuffer module lands.
+pub struct Pipeline<'a, C, L, D, CS, DS, SS>
     where C: 'a + HasFramebuffer + HasProgram + HasTessellation + HasTexture,
           L: 'a + Layerable,
           D: 'a + Dimensionable,
           D::Size: Copy,
           CS: 'a + ColorSlot<C, L, D>,
-          DS: 'a + DepthSlot<C, L, D> {
+          DS: 'a + DepthSlot<C, L, D>,
+          SS: 'a + StencilSlot<C, L, D> {
   /// The embedded framebuffer.
-  pub framebuffer: &'a Framebuffer<C, L, D, CS, DS>,
-  /// The color used to clean the framebuffer when  executing the pipeline.
-  pub clear_color: [f32; 4],
+  pub framebuffer: &'a Framebuffer<C, L, D, CS, DS, SS>,
+  /// The colors used to clear the framebuffer’s color attachments when executing the pipeline,
+  /// one per attachment and in the same order as `CS`’s tuple of pixel formats (a single-element
+  /// `Vec` for a non-MRT, single-attachment `CS`).
+  pub clear_color: Vec<[f32; 4]>,
+  /// Earlier passes to render, in order, before this pipeline’s own shading commands. Each pass
+  /// is a full `Pipeline` with its own offscreen `Framebuffer`; its color attachments can then be
+  /// bound as input textures (by wrapping a shading command in a `Pipe`) to this pipeline’s
+  /// shading commands, turning the
+  /// flat list of shading commands into a small render graph.
+  pub passes: Vec<&'a SomePipeline<C>>,
   /// Shading commands to render into the embedded framebuffer.
   pub shading_commands: Vec<&'a SomeShadingCommand> // TODO: can we use a slice instead? &'a […]
 }
 
-impl<'a, C, L, D, CS, DS> Pipeline<'a, C, L, D, CS, DS>
+impl<'a, C, L, D, CS, DS, SS> Pipeline<'a, C, L, D, CS, DS, SS>
     where C: HasPipeline,
           L: Layerable,
           D: Dimensionable,
           D::Size: Copy,
           CS: ColorSlot<C, L, D>,
-          DS: DepthSlot<C, L, D> {
+          DS: DepthSlot<C, L, D>,
+          SS: StencilSlot<C, L, D> {
   /// Create a new pipeline.
-  pub fn new(framebuffer: &'a Framebuffer<C, L, D, CS, DS>, clear_color: [f32; 4], shading_commands: Vec<&'a SomeShadingCommand>) -> Self {
+  pub fn new(framebuffer: &'a Framebuffer<C, L, D, CS, DS, SS>, clear_color: Vec<[f32; 4]>, passes: Vec<&'a SomePipeline<C>>, shading_commands: Vec<&'a SomeShadingCommand>) -> Self {
     Pipeline {
       framebuffer: framebuffer,
       clear_color: clear_color,
+      passes: passes,
       shading_commands: shading_commands
     }
   }
 
-  /// Run a `Pipeline`.
+  /// Run a `Pipeline`. Texture-unit allocation starts fresh at unit 0; wrap this pipeline in a
+  /// `Pipe` and call `run_pipeline` on that instead if it needs to run downstream of other bound
+  /// textures.
   pub fn run(&self) {
-    C::run_pipeline(self);
+    C::run_pipeline(self, 0);
+  }
+}
+
+/// This trait is used to add existential quantification to `Pipeline`s so that a `Pipeline` can
+/// hold a heterogeneous list of earlier passes – each with its own framebuffer and color/depth/
+/// stencil slot types – to be rendered before its own shading commands.
+pub trait SomePipeline<C> where C: HasPipeline {
+  /// Run this pass. `next_unit` is the first texture unit free for this pass (and anything it
+  /// runs) to allocate.
+  fn run_pipeline(&self, next_unit: u32);
+}
+
+impl<'a, C, L, D, CS, DS, SS> SomePipeline<C> for Pipeline<'a, C, L, D, CS, DS, SS>
+    where C: 'a + HasPipeline,
+          L: Layerable,
+          D: Dimensionable,
+          D::Size: Copy,
+          CS: ColorSlot<C, L, D>,
+          DS: DepthSlot<C, L, D>,
+          SS: StencilSlot<C, L, D> {
+  fn run_pipeline(&self, next_unit: u32) {
+    C::run_pipeline(self, next_unit);
+  }
+}
+
+/// Binding `Pipe::textures` before running a wrapped `Pipeline` is how you feed it textures “at
+/// the top level”: pass it to `passes`, or call `run_pipeline` on it directly instead of calling
+/// `Pipeline::run`. The wrapped pipeline’s own texture allocation (its `passes` and
+/// `shading_commands`) starts right after the units `textures` occupies, so it can’t collide with
+/// them.
+impl<'a, C, L, D, CS, DS, SS> SomePipeline<C> for Pipe<'a, C, Pipeline<'a, C, L, D, CS, DS, SS>>
+    where C: 'a + HasPipeline,
+          L: Layerable,
+          D: Dimensionable,
+          D::Size: Copy,
+          CS: ColorSlot<C, L, D>,
+          DS: DepthSlot<C, L, D>,
+          SS: StencilSlot<C, L, D> {
+  fn run_pipeline(&self, next_unit: u32) {
+    let next_unit = self.bind(next_unit);
+    C::run_pipeline(&self.next, next_unit);
   }
 }
 
 /// This trait is used to add existential quantification to `ShadingCommands`. It should be
 /// implemented by backends to enable their use in `Pipeline`s.
 pub trait SomeShadingCommand { // TODO: maybe we can remove that and see how to type erase ShadingCommand?
-  /// Execute a shading command.
-  fn run_shading_command(&self);
+  /// Execute a shading command. `next_unit` is the first texture unit free for this command (and
+  /// its render commands) to allocate.
+  fn run_shading_command(&self, next_unit: u32);
 }
 
 impl<'a, C, T> SomeShadingCommand for ShadingCommand<'a, C, T> where C: 'a + HasPipeline {
-  fn run_shading_command(&self) {
-    C::run_shading_command(self);
+  fn run_shading_command(&self, next_unit: u32) {
+    C::run_shading_command(self, &[], next_unit);
+  }
+}
+
+/// The wrapped shading command’s own texture allocation (its `render_commands`) starts right
+/// after the units `textures` occupies, so it can’t collide with them.
+impl<'a, C, T> SomeShadingCommand for Pipe<'a, C, ShadingCommand<'a, C, T>> where C: 'a + HasPipeline {
+  fn run_shading_command(&self, next_unit: u32) {
+    let bound_from = next_unit;
+    let next_unit = self.bind(next_unit);
+    let units: Vec<u32> = (bound_from..next_unit).collect();
+
+    C::run_shading_command(&self.next, &units, next_unit);
   }
 }
 
 /// A dynamic *shading command*. A shading command gathers *render commands* under a shader
 /// `Program`.
-pub struct ShadingCommand<'a, C, T> where C: 'a + HasProgram + HasTessellation, T: 'a {
+pub struct ShadingCommand<'a, C, T> where C: 'a + HasProgram + HasTessellation + HasTexture, T: 'a {
   /// Embedded program.
   pub program: &'a Program<C, T>,
   /// Shader interface update function.
   ///
-  /// This function is called whenever the shading command is executed, and only once per execution.
-  /// You can use it to update uniforms.
-  pub update: Box<Fn(&T) + 'a>,
-  /// Render commands to execute for this shading command.
-  pub render_commands: Vec<RenderCommand<'a, C, T>>
+  /// This function is called whenever the shading command is executed, and only once per
+  /// execution. You can use it to update uniforms. The second argument holds the texture unit
+  /// each texture of the wrapping `Pipe` got bound to, in the same order (empty if this shading
+  /// command isn’t wrapped in a `Pipe`).
+  pub update: Box<Fn(&T, &[u32]) + 'a>,
+  /// Render commands to execute for this shading command, each wrapped in a `Pipe` carrying the
+  /// textures (if any) to bind before it’s drawn.
+  pub render_commands: Vec<Pipe<'a, C, RenderCommand<'a, C, T>>>
 }
 
-impl<'a, C, T> ShadingCommand<'a, C, T> where C: 'a + HasProgram + HasTessellation {
+impl<'a, C, T> ShadingCommand<'a, C, T> where C: 'a + HasProgram + HasTessellation + HasTexture {
   /// Create a new shading command.
-  pub fn new<F: Fn(&T) + 'a>(program: &'a Program<C, T>, update: F, render_commands: Vec<RenderCommand<'a, C, T>>) -> Self {
+  pub fn new<F: Fn(&T, &[u32]) + 'a>(program: &'a Program<C, T>, update: F, render_commands: Vec<Pipe<'a, C, RenderCommand<'a, C, T>>>) -> Self {
     ShadingCommand {
       program: program,
       update: Box::new(update),
@@ -111,16 +360,28 @@ pub struct RenderCommand<'a, C, T> where C: 'a + HasTessellation {
   /// `Some(equation, source, destination)` if you want to perform a color blending with the
   /// `equation` formula and with the `source` and `destination` blending factors.
   pub blending: Option<(blending::Equation, blending::Factor, blending::Factor)>,
-  /// Should a depth test be performed?
-  pub depth_test: bool,
+  /// Depth test configuration. Set to `None` if you don’t want a depth test performed, or to
+  /// `Some(depth_test)` to compare fragments with `depth_test.comparison` and write to the depth
+  /// buffer whenever `depth_test.write` is set. `comparison` and `write` are meant to map to
+  /// `glDepthFunc` and `glDepthMask` respectively on OpenGL-like backends, but that mapping is
+  /// backend work this core crate doesn’t implement.
+  pub depth_test: Option<DepthTest>,
+  /// Stencil test configuration. Set to `None` if you don’t want a stencil test performed, or to
+  /// `Some(stencil_test)` to have fragments compared – and the stencil buffer updated –
+  /// according to `stencil_test`.
+  pub stencil_test: Option<StencilTest>,
   /// Shader interface update function.
   ///
-  /// This function is called whenever the render command is executed, and only once per execution.
-  /// You can use it to update uniforms.
-  pub update: Box<Fn(&T) + 'a>,
-  /// The embedded tessellation.
-  pub tessellation: &'a Tessellation<C>,
-  /// Number of instances of the tessellation to render.
+  /// This function is called whenever the render command is executed, and only once per
+  /// execution. You can use it to update uniforms. The second argument holds the texture unit
+  /// each texture of the wrapping `Pipe` got bound to, in the same order (empty if this render
+  /// command isn’t wrapped in a `Pipe`).
+  pub update: Box<Fn(&T, &[u32]) + 'a>,
+  /// The embedded tessellations. They all share this command’s blending, depth test, stencil
+  /// test and rasterization state, so backends are expected to set that state up once and then
+  /// draw each tessellation in turn instead of repeating the state change per draw.
+  pub tessellations: Vec<&'a Tessellation<C>>,
+  /// Number of instances of each tessellation to render.
   pub instances: u32,
   /// Rasterization size for points and lines.
   pub rasterization_size: Option<f32>
@@ -128,14 +389,15 @@ pub struct RenderCommand<'a, C, T> where C: 'a + HasTessellation {
 
 impl<'a, C, T> RenderCommand<'a, C, T> where C: 'a + HasTessellation {
   /// Create a new render command.
-  pub fn new<F: Fn(&T) + 'a>(blending: Option<(blending::Equation, blending::Factor, blending::Factor)>, depth_test: bool, update: F, tessellation: &'a Tessellation<C>, instances: u32, rasterization_size: Option<f32>) -> Self {
+  pub fn new<F: Fn(&T, &[u32]) + 'a>(blending: Option<(blending::Equation, blending::Factor, blending::Factor)>, depth_test: Option<DepthTest>, stencil_test: Option<StencilTest>, update: F, tessellations: Vec<&'a Tessellation<C>>, instances: u32, rasterization_size: Option<f32>) -> Self {
     RenderCommand {
       blending: blending,
       depth_test: depth_test,
+      stencil_test: stencil_test,
       update: Box::new(update),
-      tessellation: tessellation,
+      tessellations: tessellations,
       instances: instances,
       rasterization_size: rasterization_size
     }
   }
-}
\ No newline at end of file
+}